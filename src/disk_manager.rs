@@ -1,20 +1,142 @@
 use std::fs::{File, OpenOptions}; // File構造体はファイルディスクリプタのラッパー
 use std::path::Path;
-use std::io::{self, prelude::*, SeekFrom};
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
 
-// ページサイズ：4096Byte固定
-const PAGE_SIZE: usize = 4096;
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
+
+use memmap2::MmapMut;
+
+// ベースページサイズ：exp=0のときの実際のページサイズ（Byte）
+const BASE_PAGE_SIZE: usize = 4096;
+
+// ページ先頭に置くメタデータプレフィックスのサイズ（size exponentを1Byteで記録する）
+// これにより各ページが「自分のサイズ」を自己申告でき、外部の索引なしに読み出せる
+const PAGE_PREFIX_SIZE: usize = 1;
+
+// サポートするsize exponentの上限（MAX_EXP未満のみ有効。ページサイズは最大でBASE_PAGE_SIZE << (MAX_EXP - 1)）
+const MAX_EXP: usize = 16;
+
+// size exponentから実際のページサイズ（Byte）を求める（base << exp）
+fn page_bytes(exp: u8) -> usize {
+    BASE_PAGE_SIZE << exp
+}
+
+// size exponentがfree_list_heads/サポート範囲に収まっているか検証する
+// ここを通さずにexpをusizeへキャストしてインデックスに使うと範囲外アクセスでpanicしうる
+fn validate_exp(exp: u8) -> io::Result<()> {
+    if (exp as usize) < MAX_EXP {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("size exponent {exp} is out of range (must be < {MAX_EXP})"),
+        ))
+    }
+}
+
+// フリーリストの終端を表す番兵値（page_idとして使われることはない想定）
+const FREE_LIST_NIL: u64 = u64::MAX;
+
+// メタページ（page 0）の先頭に書き込む識別子（"MRDB"のASCIIコードを並べたもの）
+// フォーマットが異なるファイルを誤って読み込まないようにするためのチェック
+const META_MAGIC: u32 = 0x4D52_4442;
+
+// メタページのフォーマットバージョン
+// レイアウトを変更したら上げる（サイズクラス別フリーリストの追加でv2とした）
+const META_FORMAT_VERSION: u32 = 2;
+
+// メタページのページID（固定でpage 0を予約する。exp 0固定、BASE_PAGE_SIZEちょうどの大きさ）
+const META_PAGE_ID: PageID = PageID(0);
+
+// 指定したオフセットから読み込む（カーソルを共有しないためシーク不要、&selfで呼べる）
+#[cfg(unix)]
+fn read_at(file: &File, offset: u64, data: &mut [u8]) -> io::Result<()> {
+    file.read_exact_at(data, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, offset: u64, data: &mut [u8]) -> io::Result<()> {
+    // seek_readは実際に読めたバイト数しか保証しないため、埋まるまでループする
+    let mut read = 0;
+    while read < data.len() {
+        let n = file.seek_read(&mut data[read..], offset + read as u64)?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"));
+        }
+        read += n;
+    }
+    Ok(())
+}
+
+// 指定したオフセットへ書き込む（カーソルを共有しないためシーク不要、&selfで呼べる）
+#[cfg(unix)]
+fn write_at(file: &File, offset: u64, data: &[u8]) -> io::Result<()> {
+    file.write_all_at(data, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, offset: u64, data: &[u8]) -> io::Result<()> {
+    let mut written = 0;
+    while written < data.len() {
+        let n = file.seek_write(&data[written..], offset + written as u64)?;
+        written += n;
+    }
+    Ok(())
+}
+
+// ページ単位でヒープファイル（またはそれに代わるストレージ）を読み書きするための抽象
+// DiskManager・MmapDeviceなど異なるバックエンドをバッファプールから同じ形で扱えるようにする
+//
+// 耐久性の契約：フリーリストやメタページが参照するデータページは、
+// そのメタページより先に安定ストレージへ確定している必要がある。DiskManagerでは
+// allocate_page/deallocate_pageが内部で呼ぶwrite_metaが、メタページを書き込む前に
+// sync_dataでそれまでに書いたデータページ（確保したページやフリーリストのリンク）を確定させる。
+// メタページ自体の書き込みはバッファされたままなので、それを確定させるには
+// 呼び出し側がsyncを呼ぶ。write_pageで書いたユーザーデータも同様に、
+// 呼び出し側がsync_data（またはsync）を呼ぶまで確定しない。
+pub trait Device {
+    fn read_page(&self, page_id: PageID, data: &mut [u8]) -> io::Result<()>;
+    fn write_page(&self, page_id: PageID, data: &[u8]) -> io::Result<()>;
+    // ファイルの内容とメタデータの両方を安定ストレージへ同期する
+    fn sync(&self) -> io::Result<()>;
+    // ファイルの内容のみを同期する（メタデータの同期を省く分、syncより軽量）
+    fn sync_data(&self) -> io::Result<()>;
+}
+
+// ページディレクトリの1エントリ：そのページのsize exponentとヒープファイル上のオフセット
+// オフセットは固定長のPAGE_SIZE*page_idではなく、手前のページまでの実サイズの累積で決まる
+#[derive(Clone, Copy)]
+struct PageEntry {
+    exp: u8,
+    offset: u64,
+}
 
 // ディスクマネージャ
 // ・ディスクへのファイル（ヒープファイル）の読み書きを行う
-// ・ヒープファイルはページ（固定長ブロック、大体は4Byte、OSのファイルシステムの読み書きサイズに合わせている）で構成され、ページ単位で読み書きを実施
+// ・ヒープファイルはページ（可変長ブロック、サイズはsize exponentで指定）で構成され、ページ単位で読み書きを実施
 // ・ページIDの採番によりヒープファイルにページを作成
+// ・page 0はメタページとして予約されており、アロケータの状態（次のページID・サイズクラス別フリーリストの先頭）を永続化する
+// ・read_page/write_pageはpositioned I/Oで実装されているため&selfで呼べ、Arc<DiskManager>として複数スレッドから共有できる
 pub struct DiskManager {
-    // ヒープファイルのファイルディスクリプタ
-    heap_file: File,
-    // 次に採番するページID（0始まり）
-    // 採番のたびにインクリメント
+    // ヒープファイルのファイルディスクリプタ（positioned I/Oのみを使うため共有可能）
+    heap_file: Arc<File>,
+    // 次に採番するページID（1始まり、page 0はメタページ）
     next_page_id: u64,
+    // サイズクラス（exp）ごとのフリーリストの先頭ページID
+    // 解放済みページのプレフィックス直後8Byteには次の空きページIDが書き込まれており、
+    // 同じサイズクラスのページ同士で単方向リストとして数珠つなぎになっている
+    free_list_heads: [Option<PageID>; MAX_EXP],
+    // ページディレクトリ：page_idをインデックスとした(exp, offset)のキャッシュ
+    // index 0（メタページ分）はダミーで、実際のユーザーページはindex 1から入る
+    page_dir: Vec<PageEntry>,
+    // page_idをインデックスとした「現在フリーリストに載っているか」のキャッシュ
+    // 二重解放の検知に使う。page_dirと同様、真実の情報源はヒープファイル自身
+    // （フリーリストを辿ったもの）で、起動時にfree_list_headsから再構築する
+    is_free: Vec<bool>,
 }
 
 // ページID（NewTypeパターン）
@@ -36,41 +158,355 @@ impl DiskManager {
             .write(true)
             .create(true)
             .open(heap_file_path)?;
+        let heap_file = Arc::new(heap_file);
 
-        // ファイルサイズの取得から次に採番するページIDを計算 
         let file_size = heap_file.metadata()?.len();
-        let next_page_id = file_size / PAGE_SIZE as u64;
 
-        Ok(Self {
+        let mut disk_manager = Self {
             heap_file,
-            next_page_id,
-        })
+            next_page_id: 1,
+            free_list_heads: [None; MAX_EXP],
+            page_dir: vec![PageEntry { exp: 0, offset: 0 }],
+            is_free: vec![false],
+        };
+
+        if file_size == 0 {
+            // 新規作成：メタページを初期状態で書き込む
+            disk_manager.write_meta()?;
+        } else {
+            // 既存ファイル：メタページからアロケータの状態を復元する
+            // （ファイルサイズからの逆算はフリーリストによる穴に弱いため使わない）
+            disk_manager.read_meta()?;
+            // ページディレクトリは永続化せず、各ページ先頭のexpプレフィックスを
+            // 順番に読みながら再構築する（ページ自身が自分のサイズを語れるため、
+            // 外部の索引を別途持つ必要がない）
+            disk_manager.rebuild_page_dir()?;
+            // is_freeも同様に永続化せず、フリーリストを辿って再構築する
+            disk_manager.mark_free_pages()?;
+        }
+
+        Ok(disk_manager)
+    }
+
+    // page 1以降を先頭から走査し、各ページのexpプレフィックスからページディレクトリを復元する
+    fn rebuild_page_dir(&mut self) -> io::Result<()> {
+        let mut offset = BASE_PAGE_SIZE as u64;
+        for _ in 1..self.next_page_id {
+            let mut exp_buf = [0u8; PAGE_PREFIX_SIZE];
+            read_at(&self.heap_file, offset, &mut exp_buf)?;
+            let exp = exp_buf[0];
+            validate_exp(exp)?;
+            self.page_dir.push(PageEntry { exp, offset });
+            offset += page_bytes(exp) as u64;
+        }
+        self.is_free.resize(self.page_dir.len(), false);
+        Ok(())
+    }
+
+    // サイズクラスごとのフリーリストを辿り、現在フリーリストに載っているページに印を付ける
+    // is_freeはヒープファイルに永続化していないため、起動のたびにこうして再構築する
+    fn mark_free_pages(&mut self) -> io::Result<()> {
+        for exp in 0..MAX_EXP {
+            let mut current = self.free_list_heads[exp];
+            while let Some(page_id) = current {
+                let idx = page_id.to_u64() as usize;
+                self.is_free[idx] = true;
+
+                let entry = self.page_dir[idx];
+                let mut next_buf = [0u8; 8];
+                read_at(&self.heap_file, entry.offset + PAGE_PREFIX_SIZE as u64, &mut next_buf)?;
+                let next = u64::from_le_bytes(next_buf);
+                current = if next == FREE_LIST_NIL { None } else { Some(PageID(next)) };
+            }
+        }
+        Ok(())
+    }
+
+    // メタページの書き込み
+    // マジックナンバー・フォーマットバージョン・次のページID・サイズクラス別フリーリストの先頭を記録する
+    // メタページが参照するデータページを先に確定させるため、書き込む前にsync_dataを呼ぶ
+    fn write_meta(&mut self) -> io::Result<()> {
+        self.heap_file.sync_data()?;
+        let mut buf = [0u8; BASE_PAGE_SIZE];
+        buf[0..4].copy_from_slice(&META_MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&META_FORMAT_VERSION.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.next_page_id.to_le_bytes());
+        for (exp, head) in self.free_list_heads.iter().enumerate() {
+            let raw = head.map_or(FREE_LIST_NIL, PageID::to_u64);
+            let start = 16 + exp * 8;
+            buf[start..start + 8].copy_from_slice(&raw.to_le_bytes());
+        }
+
+        write_at(&self.heap_file, META_PAGE_ID.to_u64(), &buf)
+    }
+
+    // メタページの読み込み
+    // マジックナンバーとフォーマットバージョンを検証した上で、アロケータの状態を復元する
+    fn read_meta(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; BASE_PAGE_SIZE];
+        read_at(&self.heap_file, META_PAGE_ID.to_u64(), &mut buf)?;
+
+        let magic = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+        if magic != META_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid meta page magic"));
+        }
+        let version = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+        if version != META_FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unsupported meta page format version",
+            ));
+        }
+
+        self.next_page_id = u64::from_le_bytes(buf[8..16].try_into().unwrap());
+        for (exp, head) in self.free_list_heads.iter_mut().enumerate() {
+            let start = 16 + exp * 8;
+            let raw = u64::from_le_bytes(buf[start..start + 8].try_into().unwrap());
+            *head = if raw == FREE_LIST_NIL { None } else { Some(PageID(raw)) };
+        }
+
+        Ok(())
     }
 
     // ページの割り当て
-    pub fn allocate_page(&mut self) -> PageID {
-        let page_id = self.next_page_id;
-        self.next_page_id += 1;
-        PageID(page_id)
+    // expで指定したサイズクラスのフリーリストに解放済みページがあればそれを再利用し、
+    // 無ければヒープファイルの末尾に新しいページを確保する
+    pub fn allocate_page(&mut self, exp: u8) -> io::Result<PageID> {
+        validate_exp(exp)?;
+
+        if let Some(page_id) = self.free_list_heads[exp as usize] {
+            // 解放済みページのプレフィックス直後8Byteから次の空きページIDを取り出し、リストを1つ進める
+            let entry = self.page_dir[page_id.to_u64() as usize];
+            let mut next_buf = [0u8; 8];
+            read_at(&self.heap_file, entry.offset + PAGE_PREFIX_SIZE as u64, &mut next_buf)?;
+            let next = u64::from_le_bytes(next_buf);
+            self.free_list_heads[exp as usize] = if next == FREE_LIST_NIL {
+                None
+            } else {
+                Some(PageID(next))
+            };
+            self.is_free[page_id.to_u64() as usize] = false;
+            // フリーリストの先頭が進んだので、メタページに書き戻して状態を永続化する
+            self.write_meta()?;
+            return Ok(page_id);
+        }
+
+        let offset = self
+            .page_dir
+            .last()
+            .map(|entry| entry.offset + page_bytes(entry.exp) as u64)
+            .unwrap_or(BASE_PAGE_SIZE as u64);
+        let page_id = PageID(self.page_dir.len() as u64);
+
+        // ページの全域（プレフィックス＋ユーザー領域）を確保する。プレフィックスだけ
+        // 書いてしまうとファイル長がページ境界より手前で終わり、MmapDeviceがその時点の
+        // ファイル長でマッピングし直した際にページの後半がマップ範囲外になってしまう
+        let mut page_buf = vec![0u8; page_bytes(exp)];
+        page_buf[0] = exp;
+        write_at(&self.heap_file, offset, &page_buf)?;
+        self.page_dir.push(PageEntry { exp, offset });
+        self.is_free.push(false);
+        self.next_page_id = self.page_dir.len() as u64;
+
+        // next_page_idが進んだので、メタページに書き戻して状態を永続化する
+        // （write_metaは確保したページをsync_dataで確定させてからメタページを書く。
+        // メタページ自体の書き込みはバッファされたままで、その確定は呼び出し側がsyncで行う）
+        self.write_meta()?;
+
+        Ok(page_id)
+    }
+
+    // ページの解放
+    // 解放したページのプレフィックス直後8Byteに、同じサイズクラスの現在のフリーリストの先頭を書き込み、
+    // 自身を新しいフリーリストの先頭にする（イントルーシブな単方向リスト）
+    // page 0（メタページ）の解放と、同じpage_idを2度解放しようとした場合は、
+    // リストやメタページを壊す前にエラーを返す
+    pub fn deallocate_page(&mut self, page_id: PageID) -> io::Result<()> {
+        if page_id.to_u64() == META_PAGE_ID.to_u64() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot deallocate the reserved meta page (page 0)",
+            ));
+        }
+
+        let entry = self.entry(page_id)?;
+        let idx = page_id.to_u64() as usize;
+        if self.is_free[idx] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "page is already on the free list (double free)",
+            ));
+        }
+
+        let prev_head = self.free_list_heads[entry.exp as usize].map_or(FREE_LIST_NIL, PageID::to_u64);
+        write_at(&self.heap_file, entry.offset + PAGE_PREFIX_SIZE as u64, &prev_head.to_le_bytes())?;
+        self.free_list_heads[entry.exp as usize] = Some(page_id);
+        self.is_free[idx] = true;
+
+        // フリーリストの先頭が変わったので、メタページに書き戻して状態を永続化する
+        self.write_meta()?;
+        Ok(())
+    }
+
+    // ページディレクトリ上のエントリを返す（MmapDeviceなど他のDeviceバックエンドが
+    // 同じオフセット計算を共有するためのアクセサ）
+    // page_idがpage_dirの範囲外（未割り当てやヒープの外）ならErrを返す。ここを通さずに
+    // page_idをusizeへキャストしてインデックスに使うと範囲外アクセスでpanicしうる
+    // （validate_expがsize exponentに対して行っているのと同じ理由）
+    fn entry(&self, page_id: PageID) -> io::Result<PageEntry> {
+        self.page_dir.get(page_id.to_u64() as usize).copied().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("page id {} is out of range (page_dir has {} entries)", page_id.to_u64(), self.page_dir.len()),
+            )
+        })
+    }
+
+    // そのページのユーザーデータ領域の(オフセット, 長さ)を返す
+    // MmapDeviceがヒープファイルへのmmap上で同じレイアウトを参照するために使う
+    fn page_extent(&self, page_id: PageID) -> io::Result<(u64, usize)> {
+        let entry = self.entry(page_id)?;
+        Ok((entry.offset + PAGE_PREFIX_SIZE as u64, page_bytes(entry.exp) - PAGE_PREFIX_SIZE))
+    }
+
+    // 現在のヒープファイルの長さ（Byte）
+    fn file_len(&self) -> io::Result<u64> {
+        self.heap_file.metadata().map(|m| m.len())
+    }
+}
+
+impl Device for DiskManager {
+    // データの読み込み（positioned I/Oのため&selfで呼べる）
+    // dataの長さはそのページのexpから決まるユーザー可視サイズと一致しなければならない
+    fn read_page(&self, page_id: PageID, data: &mut [u8]) -> io::Result<()> {
+        let (offset, usable) = self.page_extent(page_id)?;
+        if data.len() != usable {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer length does not match page size"));
+        }
+        read_at(&self.heap_file, offset, data)
+    }
+
+    // データの書き込み（positioned I/Oのため&selfで呼べる）
+    fn write_page(&self, page_id: PageID, data: &[u8]) -> io::Result<()> {
+        let (offset, usable) = self.page_extent(page_id)?;
+        if data.len() != usable {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer length does not match page size"));
+        }
+        write_at(&self.heap_file, offset, data)
+    }
+
+    // ヒープファイルの内容とメタデータを安定ストレージへ同期する
+    fn sync(&self) -> io::Result<()> {
+        self.heap_file.sync_all()
+    }
+
+    // ヒープファイルの内容のみを安定ストレージへ同期する
+    fn sync_data(&self) -> io::Result<()> {
+        self.heap_file.sync_data()
+    }
+}
+
+// 読み込み中心のワークロード向けのDeviceバックエンド
+// ヒープファイルをまるごとmmapし、read_page/write_pageはseek+read_exactのシステムコールを
+// 経由せずmmap領域に直接アクセスする。ページディレクトリ・フリーリスト・メタページの
+// 管理はDiskManagerにそのまま委譲し、layoutの計算（page_extent）だけを共有する。
+// write_pageで触れた範囲はdirty_rangesに記録しておき、sync/sync_dataではmmap全体ではなく
+// その範囲だけをmsync（flush_range）する。ランダムI/Oのワークロードではmmapのごく一部しか
+// 更新されないことが多く、毎回全域をmsyncすると無駄が大きいため。
+pub struct MmapDevice {
+    disk: DiskManager,
+    // ヒープファイル全体を覆うmmap領域。allocate_pageでファイルがこの範囲を超えて
+    // 伸びたら張り直す
+    mmap: RwLock<MmapMut>,
+    // 前回のsync/sync_data以降にwrite_pageで書き込まれた(offset, 長さ)の一覧
+    // sync/sync_dataで読み出してflush_rangeした後に空にする
+    dirty_ranges: Mutex<Vec<(usize, usize)>>,
+}
+
+impl MmapDevice {
+    pub fn new(heap_file_path: impl AsRef<Path>) -> io::Result<Self> {
+        let disk = DiskManager::new(heap_file_path)?;
+        let mmap = Self::map(&disk)?;
+        Ok(Self {
+            disk,
+            mmap: RwLock::new(mmap),
+            dirty_ranges: Mutex::new(Vec::new()),
+        })
     }
 
-    // データの読み込み
-    pub fn read(&mut self, page_id: PageID, data: &mut [u8]) -> io::Result<()> {
-        // ファイルディスクリプタを読み込むデータの先頭にシーク
-        let offset = PAGE_SIZE as u64 * page_id.to_u64();
-        self.heap_file.seek(SeekFrom::Start(offset))?;
+    fn map(disk: &DiskManager) -> io::Result<MmapMut> {
+        unsafe { MmapMut::map_mut(disk.heap_file.as_ref()) }
+    }
 
-        // データの読み込み
-        self.heap_file.read_exact(data)
+    // DiskManagerと同じ採番・フリーリストでページを確保し、
+    // ファイルがmmap済みの範囲を超えて伸びていたら張り直す
+    pub fn allocate_page(&mut self, exp: u8) -> io::Result<PageID> {
+        let page_id = self.disk.allocate_page(exp)?;
+        self.remap_if_grown()?;
+        Ok(page_id)
     }
 
-    // データの書き込み
-    pub fn write(&mut self, page_id: PageID, data: &[u8]) -> io::Result<()> {
-        let offset = PAGE_SIZE as u64 * page_id.to_u64();
-        self.heap_file.seek(SeekFrom::Start(offset))?;
+    pub fn deallocate_page(&mut self, page_id: PageID) -> io::Result<()> {
+        self.disk.deallocate_page(page_id)
+    }
+
+    fn remap_if_grown(&mut self) -> io::Result<()> {
+        let file_len = self.disk.file_len()?;
+        if file_len > self.mmap.read().unwrap().len() as u64 {
+            *self.mmap.write().unwrap() = Self::map(&self.disk)?;
+        }
+        Ok(())
+    }
 
-        // データの書き込み
-        self.heap_file.write_all(data)
+    // write_pageが記録したdirty_rangesだけをmsyncし、記録を空にする
+    fn flush_dirty_ranges(&self) -> io::Result<()> {
+        let ranges = std::mem::take(&mut *self.dirty_ranges.lock().unwrap());
+        let mmap = self.mmap.read().unwrap();
+        for (offset, len) in ranges {
+            mmap.flush_range(offset, len)?;
+        }
+        Ok(())
+    }
+}
+
+impl Device for MmapDevice {
+    // mmap領域からコピーして読み込む。seek+read_exactのシステムコールは発生しない
+    fn read_page(&self, page_id: PageID, data: &mut [u8]) -> io::Result<()> {
+        let (offset, usable) = self.disk.page_extent(page_id)?;
+        if data.len() != usable {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer length does not match page size"));
+        }
+        let mmap = self.mmap.read().unwrap();
+        let start = offset as usize;
+        data.copy_from_slice(&mmap[start..start + usable]);
+        Ok(())
+    }
+
+    // mmap領域へ書き込む。ディスクへ反映されるのはsync/sync_dataでflushしたとき
+    fn write_page(&self, page_id: PageID, data: &[u8]) -> io::Result<()> {
+        let (offset, usable) = self.disk.page_extent(page_id)?;
+        if data.len() != usable {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer length does not match page size"));
+        }
+        let mut mmap = self.mmap.write().unwrap();
+        let start = offset as usize;
+        mmap[start..start + usable].copy_from_slice(data);
+        self.dirty_ranges.lock().unwrap().push((start, usable));
+        Ok(())
+    }
+
+    // 前回のsync/sync_data以降に書き込まれた範囲だけをmsync（flush_range）し、
+    // 続けてヒープファイルの内容とメタデータを同期する
+    fn sync(&self) -> io::Result<()> {
+        self.flush_dirty_ranges()?;
+        self.disk.sync()
+    }
+
+    // 前回のsync/sync_data以降に書き込まれた範囲だけをmsync（flush_range）し、
+    // 続けてヒープファイルの内容のみを同期する
+    fn sync_data(&self) -> io::Result<()> {
+        self.flush_dirty_ranges()?;
+        self.disk.sync_data()
     }
 }
 
@@ -80,37 +516,280 @@ mod tests {
     use super::*;
     use tempfile::NamedTempFile;
 
+    // exp 0のページにおける、プレフィックスを除いたユーザー可視サイズ
+    const PAGE_SIZE: usize = BASE_PAGE_SIZE - PAGE_PREFIX_SIZE;
+
     #[test]
     fn test() {
         let (_, data_file_path) = NamedTempFile::new().unwrap().into_parts();
         let mut disk = DiskManager::new(&data_file_path).unwrap();
 
         // PageIDの採番
-        let test_page_id = disk.allocate_page();
+        let test_page_id = disk.allocate_page(0).unwrap();
 
         // tempファイルへの書き込み
         let mut data = Vec::with_capacity(PAGE_SIZE); // ページサイズのベクターを確保
         data.extend_from_slice(b"test"); // 文字列の格納
         data.resize(PAGE_SIZE, 0); // 0-padding
 
-        disk.write(test_page_id, &data).unwrap();
+        disk.write_page(test_page_id, &data).unwrap();
 
-        let second_test_page_id = disk.allocate_page();
+        let second_test_page_id = disk.allocate_page(0).unwrap();
         let mut data2 = Vec::with_capacity(PAGE_SIZE);
         data2.extend_from_slice(b"second_test");
         data2.resize(PAGE_SIZE, 0);
 
-        disk.write(second_test_page_id, &data2).unwrap();
+        disk.write_page(second_test_page_id, &data2).unwrap();
 
         drop(disk);
 
         // tempファイルの読み込み
-        let mut disk2 = DiskManager::new(data_file_path).unwrap();
+        let disk2 = DiskManager::new(data_file_path).unwrap();
 
         let mut buffer = vec![0; PAGE_SIZE];
-        disk2.read(test_page_id, &mut buffer);
+        disk2.read_page(test_page_id, &mut buffer).unwrap();
         assert_eq!(data, buffer);
-        disk2.read(second_test_page_id, &mut buffer);
+        disk2.read_page(second_test_page_id, &mut buffer).unwrap();
         assert_eq!(data2, buffer);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_deallocate_page_is_reused() {
+        let (_, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(&data_file_path).unwrap();
+
+        let page_id = disk.allocate_page(0).unwrap();
+        let next_page_id = disk.allocate_page(0).unwrap();
+
+        disk.deallocate_page(page_id).unwrap();
+
+        // 解放したページIDが再利用され、next_page_idは伸びない
+        let reused_page_id = disk.allocate_page(0).unwrap();
+        assert_eq!(reused_page_id.to_u64(), page_id.to_u64());
+
+        let fresh_page_id = disk.allocate_page(0).unwrap();
+        assert_eq!(fresh_page_id.to_u64(), next_page_id.to_u64() + 1);
+    }
+
+    #[test]
+    fn test_double_free_is_rejected() {
+        let (_, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(&data_file_path).unwrap();
+
+        let page_id = disk.allocate_page(0).unwrap();
+        disk.deallocate_page(page_id).unwrap();
+
+        // 同じページをもう一度解放しようとするとエラーになり、フリーリストは壊れない
+        assert!(disk.deallocate_page(page_id).is_err());
+
+        // フリーリストは依然として正しく、解放したページは一度だけ返ってくる
+        let reused_page_id = disk.allocate_page(0).unwrap();
+        assert_eq!(reused_page_id.to_u64(), page_id.to_u64());
+        assert_ne!(disk.allocate_page(0).unwrap().to_u64(), page_id.to_u64());
+    }
+
+    #[test]
+    fn test_deallocate_meta_page_is_rejected() {
+        let (_, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(&data_file_path).unwrap();
+
+        // PageIDは公開されたnewtypeなので、呼び出し側はPageID(0)を自由に作れてしまう。
+        // is_free[0]は通常のallocate経路を通らないため常にfalseのままであり、
+        // 二重解放チェックだけではメタページの解放を防げない。専用のガードが必要。
+        assert!(disk.deallocate_page(PageID(0)).is_err());
+
+        // メタページが無事なら、通常のアロケーションは引き続き正しく動く
+        let page_id = disk.allocate_page(0).unwrap();
+        assert_eq!(page_id.to_u64(), 1);
+    }
+
+    #[test]
+    fn test_allocate_page_starts_at_one() {
+        let (_, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(&data_file_path).unwrap();
+
+        // page 0はメタページとして予約されているので、最初のユーザーページは1になる
+        let page_id = disk.allocate_page(0).unwrap();
+        assert_eq!(page_id.to_u64(), 1);
+    }
+
+    #[test]
+    fn test_meta_page_survives_restart() {
+        let (_, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(&data_file_path).unwrap();
+
+        disk.allocate_page(0).unwrap();
+        let freed_page_id = disk.allocate_page(0).unwrap();
+        disk.deallocate_page(freed_page_id).unwrap();
+        disk.write_meta().unwrap();
+
+        drop(disk);
+
+        let mut disk2 = DiskManager::new(&data_file_path).unwrap();
+        // フリーリストの先頭がメタページ経由で復元され、再利用される
+        let reused_page_id = disk2.allocate_page(0).unwrap();
+        assert_eq!(reused_page_id.to_u64(), freed_page_id.to_u64());
+    }
+
+    #[test]
+    fn test_read_write_take_shared_reference() {
+        let (_, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(&data_file_path).unwrap();
+        let page_id = disk.allocate_page(0).unwrap();
+        let disk = Arc::new(disk);
+
+        // read_page/write_pageは&selfなので、Arcに包んで共有できる
+        let mut data = vec![0u8; PAGE_SIZE];
+        data[0] = 1;
+        disk.write_page(page_id, &data).unwrap();
+
+        let mut buffer = vec![0u8; PAGE_SIZE];
+        disk.read_page(page_id, &mut buffer).unwrap();
+        assert_eq!(buffer[0], 1);
+    }
+
+    #[test]
+    fn test_sync_and_sync_data() {
+        let (_, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(&data_file_path).unwrap();
+
+        let page_id = disk.allocate_page(0).unwrap();
+        disk.write_page(page_id, &vec![0u8; PAGE_SIZE]).unwrap();
+
+        // データページをsync_dataで確定させてからメタページをsyncで確定させる
+        disk.sync_data().unwrap();
+        disk.write_meta().unwrap();
+        disk.sync().unwrap();
+    }
+
+    #[test]
+    fn test_variable_page_sizes() {
+        let (_, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(&data_file_path).unwrap();
+
+        // exp 0（4096Byte）とexp 1（8192Byte）のページを混在させて確保する
+        let small_page_id = disk.allocate_page(0).unwrap();
+        let large_page_id = disk.allocate_page(1).unwrap();
+
+        let small_data = vec![1u8; page_bytes(0) - PAGE_PREFIX_SIZE];
+        let large_data = vec![2u8; page_bytes(1) - PAGE_PREFIX_SIZE];
+
+        disk.write_page(small_page_id, &small_data).unwrap();
+        disk.write_page(large_page_id, &large_data).unwrap();
+
+        drop(disk);
+
+        // 再オープンしてもページディレクトリがexpプレフィックスから正しく再構築される
+        let disk2 = DiskManager::new(&data_file_path).unwrap();
+
+        let mut small_buffer = vec![0u8; page_bytes(0) - PAGE_PREFIX_SIZE];
+        let mut large_buffer = vec![0u8; page_bytes(1) - PAGE_PREFIX_SIZE];
+        disk2.read_page(small_page_id, &mut small_buffer).unwrap();
+        disk2.read_page(large_page_id, &mut large_buffer).unwrap();
+
+        assert_eq!(small_buffer, small_data);
+        assert_eq!(large_buffer, large_data);
+    }
+
+    #[test]
+    fn test_allocate_page_rejects_out_of_range_exp() {
+        let (_, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(&data_file_path).unwrap();
+
+        // MAX_EXP以上のexpはu8としては「有効」でもサイズクラス配列の範囲外になるため、
+        // panicではなくErrを返す
+        assert!(disk.allocate_page(MAX_EXP as u8).is_err());
+        assert!(disk.allocate_page(u8::MAX).is_err());
+    }
+
+    #[test]
+    fn test_read_page_rejects_mismatched_buffer_length() {
+        let (_, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(&data_file_path).unwrap();
+        let page_id = disk.allocate_page(0).unwrap();
+
+        let mut too_small = vec![0u8; PAGE_SIZE - 1];
+        assert!(disk.read_page(page_id, &mut too_small).is_err());
+    }
+
+    #[test]
+    fn test_out_of_range_page_id_is_rejected() {
+        let (_, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(&data_file_path).unwrap();
+        disk.allocate_page(0).unwrap();
+
+        // page_dirにまだ存在しないpage_id（未割り当て、あるいはヒープの外）は、
+        // 範囲外のexpと同様にpanicではなくErrを返す
+        let unallocated = PageID(999);
+        assert!(disk.read_page(unallocated, &mut vec![0u8; PAGE_SIZE]).is_err());
+        assert!(disk.write_page(unallocated, &vec![0u8; PAGE_SIZE]).is_err());
+        assert!(disk.deallocate_page(unallocated).is_err());
+    }
+
+    #[test]
+    fn test_mmap_device_read_write() {
+        let (_, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut mmap_device = MmapDevice::new(&data_file_path).unwrap();
+
+        let page_id = mmap_device.allocate_page(0).unwrap();
+        let mut data = Vec::with_capacity(PAGE_SIZE);
+        data.extend_from_slice(b"mmap");
+        data.resize(PAGE_SIZE, 0);
+
+        mmap_device.write_page(page_id, &data).unwrap();
+        mmap_device.sync().unwrap();
+
+        let mut buffer = vec![0u8; PAGE_SIZE];
+        mmap_device.read_page(page_id, &mut buffer).unwrap();
+        assert_eq!(data, buffer);
+    }
+
+    #[test]
+    fn test_mmap_device_only_flushes_dirty_ranges() {
+        let (_, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut mmap_device = MmapDevice::new(&data_file_path).unwrap();
+
+        let page_id = mmap_device.allocate_page(0).unwrap();
+        mmap_device.write_page(page_id, &vec![1u8; PAGE_SIZE]).unwrap();
+        // write_pageが書いたページのユーザー領域だけがdirty_rangesに記録される
+        assert_eq!(
+            *mmap_device.dirty_ranges.lock().unwrap(),
+            vec![(BASE_PAGE_SIZE + PAGE_PREFIX_SIZE, PAGE_SIZE)]
+        );
+        mmap_device.sync().unwrap();
+
+        // 1回目のsyncでdirty_rangesは空になり、何も書き込んでいない2回目のsyncは
+        // flush_rangeを呼ばず、既にflush済みの範囲を壊さない
+        assert!(mmap_device.dirty_ranges.lock().unwrap().is_empty());
+        mmap_device.sync().unwrap();
+
+        let mut buffer = vec![0u8; PAGE_SIZE];
+        mmap_device.read_page(page_id, &mut buffer).unwrap();
+        assert_eq!(buffer, vec![1u8; PAGE_SIZE]);
+
+        // sync後の書き込みも次のsyncでちゃんと反映される
+        mmap_device.write_page(page_id, &vec![2u8; PAGE_SIZE]).unwrap();
+        mmap_device.sync().unwrap();
+        mmap_device.read_page(page_id, &mut buffer).unwrap();
+        assert_eq!(buffer, vec![2u8; PAGE_SIZE]);
+    }
+
+    #[test]
+    fn test_mmap_device_remaps_on_growth() {
+        let (_, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut mmap_device = MmapDevice::new(&data_file_path).unwrap();
+
+        // 最初のmmapに収まらないページ数を確保しても読み書きできる
+        let page_ids: Vec<_> = (0..8).map(|_| mmap_device.allocate_page(0).unwrap()).collect();
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            let data = vec![i as u8; PAGE_SIZE];
+            mmap_device.write_page(page_id, &data).unwrap();
+        }
+
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            let mut buffer = vec![0u8; PAGE_SIZE];
+            mmap_device.read_page(page_id, &mut buffer).unwrap();
+            assert_eq!(buffer, vec![i as u8; PAGE_SIZE]);
+        }
+    }
+}